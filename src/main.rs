@@ -4,23 +4,44 @@ use argon2::{
 };
 use axum::{
     Json, Router,
-    extract::{Form, FromRequest, Path, Request, State, rejection},
+    extract::{
+        Form, FromRef, FromRequest, FromRequestParts, Multipart, Path, Query, Request, State,
+        rejection,
+    },
+    http::{header, request::Parts},
     response::{IntoResponse, Response},
     routing::{get, post},
 };
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use headers::{Authorization, HeaderMapExt, authorization::Bearer};
 use http::StatusCode;
+use image::{ImageFormat, imageops::FilterType};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use regex::Regex;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use sqlx::postgres::PgPool;
 use std::sync::LazyLock;
 use thiserror::Error;
 use tower_http::cors::{Any, CorsLayer};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
 use uuid::Uuid;
 use validator::Validate;
 
 static ARGON2: LazyLock<Argon2> = LazyLock::new(Argon2::default);
 static UPPERCASE_RE: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r".*[A-Z].*").expect("Invalid uppercase regex pattern"));
+
+const ACCESS_TOKEN_TTL_SECONDS: i64 = 15 * 60;
+const REFRESH_TOKEN_TTL_SECONDS: i64 = 7 * 24 * 60 * 60;
+const AVATAR_THUMBNAIL_SIZE: u32 = 256;
+const AVATAR_CONTENT_TYPE: &str = "image/png";
+const DEFAULT_PAGE_LIMIT: u32 = 20;
+const MAX_PAGE_LIMIT: u32 = 100;
+const ACCESS_TOKEN_TYPE: &str = "access";
+const REFRESH_TOKEN_TYPE: &str = "refresh";
+
 #[derive(Serialize, Deserialize)]
 struct User {
     id: Uuid,
@@ -30,6 +51,30 @@ struct User {
     team_id: Option<Uuid>,
     group_id: Option<Uuid>,
     password: String,
+    session_epoch: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UserProfile {
+    id: Uuid,
+    username: String,
+    primary_email_address: String,
+    organization_id: Option<Uuid>,
+    team_id: Option<Uuid>,
+    group_id: Option<Uuid>,
+}
+
+impl From<User> for UserProfile {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            primary_email_address: user.primary_email_address,
+            organization_id: user.organization_id,
+            team_id: user.team_id,
+            group_id: user.group_id,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -37,46 +82,95 @@ pub enum ServerError {
     #[error(transparent)]
     PasswordHashError(#[from] password_hash::Error),
     #[error(transparent)]
-    DatabaseError(#[from] sqlx::Error),
+    DatabaseError(sqlx::Error),
     #[error(transparent)]
     ValidationError(#[from] validator::ValidationErrors),
     #[error(transparent)]
     JsonRejectionError(#[from] rejection::JsonRejection),
+    #[error(transparent)]
+    QueryRejectionError(#[from] rejection::QueryRejection),
+    #[error("invalid or malformed token")]
+    InvalidToken,
+    #[error("missing, invalid or revoked credentials")]
+    Unauthorized,
+    #[error("not allowed to perform this action")]
+    Forbidden,
+    #[error("a user with that {field} already exists")]
+    UserExists { field: &'static str },
+    #[error("unsupported image type, expected PNG, JPEG or WebP")]
+    UnsupportedMediaType,
+    #[error("the uploaded file is not a valid image")]
+    ImageError,
+    #[error("invalid pagination cursor")]
+    InvalidCursor,
+    #[error("not found")]
+    NotFound,
+}
+
+impl From<sqlx::Error> for ServerError {
+    fn from(err: sqlx::Error) -> Self {
+        if let sqlx::Error::Database(ref db_err) = err {
+            if db_err.is_unique_violation() {
+                let field = match db_err.constraint() {
+                    Some(constraint) if constraint.contains("email") => "email",
+                    Some(constraint) if constraint.contains("username") => "username",
+                    _ => "value",
+                };
+                return ServerError::UserExists { field };
+            }
+        }
+        ServerError::DatabaseError(err)
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct ErrorResponse {
     message: String,
 }
 impl IntoResponse for ServerError {
     fn into_response(self) -> Response {
         let (status, message) = match self {
-            ServerError::DatabaseError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
+            ServerError::DatabaseError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string())
+            }
             ServerError::ValidationError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
             ServerError::PasswordHashError(_) => (StatusCode::BAD_REQUEST, self.to_string()),
             ServerError::JsonRejectionError(rejection) => {
                 (rejection.status(), rejection.body_text())
             }
+            ServerError::QueryRejectionError(rejection) => {
+                (rejection.status(), rejection.body_text())
+            }
+            ServerError::InvalidToken => (StatusCode::UNAUTHORIZED, self.to_string()),
+            ServerError::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()),
+            ServerError::Forbidden => (StatusCode::FORBIDDEN, self.to_string()),
+            ServerError::UserExists { .. } => (StatusCode::CONFLICT, self.to_string()),
+            ServerError::UnsupportedMediaType => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, self.to_string())
+            }
+            ServerError::ImageError => (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()),
+            ServerError::InvalidCursor => (StatusCode::BAD_REQUEST, self.to_string()),
+            ServerError::NotFound => (StatusCode::NOT_FOUND, self.to_string()),
         };
         (status, Json(ErrorResponse { message })).into_response()
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 struct Team {
     id: Uuid,
     name: String,
     organization_id: Uuid,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 struct Group {
     id: Uuid,
     name: String,
     team_id: Uuid,
 }
 
-#[derive(Deserialize, Serialize, Validate)]
+#[derive(Deserialize, Serialize, Validate, ToSchema)]
 struct RegisterInfo {
     #[validate(length(min = 3, max = 30,))]
     username: String,
@@ -91,7 +185,7 @@ struct RegisterInfo {
     confirm_password: String,
 }
 
-#[derive(Deserialize, Serialize, Validate)]
+#[derive(Deserialize, Serialize, Validate, ToSchema)]
 struct LoginInfo {
     #[validate(length(min = 3, max = 30,))]
     username: String,
@@ -115,17 +209,225 @@ where
     }
 }
 
+struct ValidatedQuery<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ServerError;
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(info) = Query::<T>::from_request_parts(parts, state).await?;
+        info.validate()?;
+        Ok(ValidatedQuery(info))
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 struct UserQuery {
     username: String,
     email: String,
 }
 
+#[derive(Deserialize, Validate, IntoParams)]
+struct PaginationQuery {
+    #[validate(range(min = 1, max = 100))]
+    limit: Option<u32>,
+    cursor: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[aliases(UserPage = PageEnvelope<UserProfile>, TeamPage = PageEnvelope<Team>, GroupPage = PageEnvelope<Group>)]
+struct PageEnvelope<T: ToSchema> {
+    items: Vec<T>,
+    next_cursor: Option<String>,
+}
+
+fn encode_cursor(id: Uuid) -> String {
+    URL_SAFE_NO_PAD.encode(id.as_bytes())
+}
+
+fn decode_cursor(cursor: &str) -> Result<Uuid, ServerError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| ServerError::InvalidCursor)?;
+    Uuid::from_slice(&bytes).map_err(|_| ServerError::InvalidCursor)
+}
+
+#[derive(Clone)]
+struct JwtKeys {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+}
+
+impl JwtKeys {
+    fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    pool: PgPool,
+    jwt_keys: JwtKeys,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccessClaims {
+    sub: Uuid,
+    exp: usize,
+    session_epoch: i64,
+    token_type: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RefreshClaims {
+    sub: Uuid,
+    exp: usize,
+    session_epoch: i64,
+    token_type: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TokenPair {
+    access_token: String,
+    refresh_token: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct AccessTokenResponse {
+    access_token: String,
+}
+
+fn issue_token_pair(
+    user_id: Uuid,
+    session_epoch: DateTime<Utc>,
+    jwt_keys: &JwtKeys,
+) -> Result<TokenPair, ServerError> {
+    let now = Utc::now();
+    let session_epoch = session_epoch.timestamp();
+
+    let access_claims = AccessClaims {
+        sub: user_id,
+        exp: (now + Duration::seconds(ACCESS_TOKEN_TTL_SECONDS)).timestamp() as usize,
+        session_epoch,
+        token_type: ACCESS_TOKEN_TYPE.to_string(),
+    };
+    let refresh_claims = RefreshClaims {
+        sub: user_id,
+        exp: (now + Duration::seconds(REFRESH_TOKEN_TTL_SECONDS)).timestamp() as usize,
+        session_epoch,
+        token_type: REFRESH_TOKEN_TYPE.to_string(),
+    };
+
+    let access_token = encode(&Header::default(), &access_claims, &jwt_keys.encoding_key)
+        .map_err(|_| ServerError::InvalidToken)?;
+    let refresh_token = encode(&Header::default(), &refresh_claims, &jwt_keys.encoding_key)
+        .map_err(|_| ServerError::InvalidToken)?;
+
+    Ok(TokenPair {
+        access_token,
+        refresh_token,
+    })
+}
+
+struct AuthenticatedUser(User);
+
+impl<S> FromRequestParts<S> for AuthenticatedUser
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ServerError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AppState::from_ref(state);
+        let bearer = parts
+            .headers
+            .typed_get::<Authorization<Bearer>>()
+            .ok_or(ServerError::Unauthorized)?;
+
+        let token_data = decode::<AccessClaims>(
+            bearer.token(),
+            &state.jwt_keys.decoding_key,
+            &Validation::default(),
+        )
+        .map_err(|_| ServerError::InvalidToken)?;
+        let claims = token_data.claims;
+
+        if claims.token_type != ACCESS_TOKEN_TYPE {
+            return Err(ServerError::InvalidToken);
+        }
+
+        let user = sqlx::query_as!(
+            User,
+            r#"
+SELECT *
+FROM users
+WHERE id = $1
+            "#,
+            claims.sub
+        )
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|_| ServerError::Unauthorized)?;
+
+        if user.session_epoch.timestamp() != claims.session_epoch {
+            return Err(ServerError::Unauthorized);
+        }
+
+        Ok(AuthenticatedUser(user))
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        get_users,
+        get_teams,
+        get_groups,
+        register_user,
+        login_user,
+        logout_user,
+        refresh_token,
+        get_current_user,
+        upload_avatar,
+        get_avatar
+    ),
+    components(schemas(
+        UserProfile,
+        Team,
+        Group,
+        RegisterInfo,
+        LoginInfo,
+        ErrorResponse,
+        TokenPair,
+        RefreshRequest,
+        AccessTokenResponse,
+        UserPage,
+        TeamPage,
+        GroupPage
+    ))
+)]
+struct ApiDoc;
+
 #[tokio::main]
 async fn main() {
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
 
     let pool = PgPool::connect(&database_url).await.unwrap();
+    let jwt_keys = JwtKeys::from_secret(jwt_secret.as_bytes());
+    let state = AppState { pool, jwt_keys };
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -142,7 +444,15 @@ async fn main() {
         .route("/api/users/find", post(find_user_by_form))
         .route("/api/register", post(register_user))
         .route("/api/login", post(login_user))
-        .with_state(pool)
+        .route("/api/refresh", post(refresh_token))
+        .route("/api/logout", post(logout_user))
+        .route("/api/me", get(get_current_user))
+        .route(
+            "/api/users/{user_id}/avatar",
+            post(upload_avatar).get(get_avatar),
+        )
+        .merge(SwaggerUi::new("/api/docs").url("/api/docs/openapi.json", ApiDoc::openapi()))
+        .with_state(state)
         .layer(cors);
 
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
@@ -151,135 +461,270 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn get_users(State(pool): State<PgPool>) -> Result<Json<Vec<User>>, ServerError> {
-    let users = sqlx::query_as!(
+#[utoipa::path(
+    get,
+    path = "/api/me",
+    responses(
+        (status = 200, description = "The authenticated user's profile", body = UserProfile),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorResponse),
+    )
+)]
+async fn get_current_user(AuthenticatedUser(user): AuthenticatedUser) -> Json<UserProfile> {
+    Json(user.into())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/users",
+    params(PaginationQuery),
+    responses(
+        (status = 200, description = "A page of users", body = UserPage),
+        (status = 400, description = "Invalid limit or cursor", body = ErrorResponse),
+        (status = 500, description = "Unexpected database error", body = ErrorResponse),
+    )
+)]
+async fn get_users(
+    State(state): State<AppState>,
+    ValidatedQuery(pagination): ValidatedQuery<PaginationQuery>,
+) -> Result<Json<PageEnvelope<UserProfile>>, ServerError> {
+    let limit = pagination
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .min(MAX_PAGE_LIMIT) as i64;
+    let cursor = pagination.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    let mut users = sqlx::query_as!(
         User,
         r#"
-SELECT * 
-FROM users 
+SELECT *
+FROM users
+WHERE $1::uuid IS NULL OR id > $1
 ORDER BY id
-        "#
+LIMIT $2
+        "#,
+        cursor,
+        limit + 1
     )
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await?;
-    Ok(Json(users))
+
+    let has_more = users.len() as i64 > limit;
+    if has_more {
+        users.truncate(limit as usize);
+    }
+    let next_cursor = if has_more {
+        users.last().map(|user| encode_cursor(user.id))
+    } else {
+        None
+    };
+
+    Ok(Json(PageEnvelope {
+        items: users.into_iter().map(UserProfile::from).collect(),
+        next_cursor,
+    }))
 }
 
-async fn get_teams(State(pool): State<PgPool>) -> Result<Json<Vec<Team>>, ServerError> {
-    let teams = sqlx::query_as!(
+#[utoipa::path(
+    get,
+    path = "/api/teams",
+    params(PaginationQuery),
+    responses(
+        (status = 200, description = "A page of teams", body = TeamPage),
+        (status = 400, description = "Invalid limit or cursor", body = ErrorResponse),
+        (status = 500, description = "Unexpected database error", body = ErrorResponse),
+    )
+)]
+async fn get_teams(
+    State(state): State<AppState>,
+    ValidatedQuery(pagination): ValidatedQuery<PaginationQuery>,
+) -> Result<Json<PageEnvelope<Team>>, ServerError> {
+    let limit = pagination
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .min(MAX_PAGE_LIMIT) as i64;
+    let cursor = pagination.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    let mut teams = sqlx::query_as!(
         Team,
         r#"
-SELECT * 
-FROM teams 
+SELECT *
+FROM teams
+WHERE $1::uuid IS NULL OR id > $1
 ORDER BY id
-        "#
+LIMIT $2
+        "#,
+        cursor,
+        limit + 1
     )
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await?;
-    Ok(Json(teams))
+
+    let has_more = teams.len() as i64 > limit;
+    if has_more {
+        teams.truncate(limit as usize);
+    }
+    let next_cursor = if has_more {
+        teams.last().map(|team| encode_cursor(team.id))
+    } else {
+        None
+    };
+
+    Ok(Json(PageEnvelope {
+        items: teams,
+        next_cursor,
+    }))
 }
 
 async fn get_users_by_team_id_path(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     Path(team_id): Path<Uuid>,
-) -> Result<Json<Vec<User>>, ServerError> {
+) -> Result<Json<Vec<UserProfile>>, ServerError> {
     let users = sqlx::query_as!(
         User,
         r#"
-SELECT * 
-FROM users 
+SELECT *
+FROM users
 WHERE team_id = $1
         "#,
         team_id
     )
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await?;
-    Ok(Json(users))
+    Ok(Json(users.into_iter().map(UserProfile::from).collect()))
 }
 
-async fn get_groups(State(pool): State<PgPool>) -> Result<Json<Vec<Group>>, ServerError> {
-    let groups = sqlx::query_as!(
+#[utoipa::path(
+    get,
+    path = "/api/groups",
+    params(PaginationQuery),
+    responses(
+        (status = 200, description = "A page of groups", body = GroupPage),
+        (status = 400, description = "Invalid limit or cursor", body = ErrorResponse),
+        (status = 500, description = "Unexpected database error", body = ErrorResponse),
+    )
+)]
+async fn get_groups(
+    State(state): State<AppState>,
+    ValidatedQuery(pagination): ValidatedQuery<PaginationQuery>,
+) -> Result<Json<PageEnvelope<Group>>, ServerError> {
+    let limit = pagination
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .min(MAX_PAGE_LIMIT) as i64;
+    let cursor = pagination.cursor.as_deref().map(decode_cursor).transpose()?;
+
+    let mut groups = sqlx::query_as!(
         Group,
         r#"
-SELECT * 
-FROM groups 
+SELECT *
+FROM groups
+WHERE $1::uuid IS NULL OR id > $1
 ORDER BY id
-        "#
+LIMIT $2
+        "#,
+        cursor,
+        limit + 1
     )
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await?;
-    Ok(Json(groups))
+
+    let has_more = groups.len() as i64 > limit;
+    if has_more {
+        groups.truncate(limit as usize);
+    }
+    let next_cursor = if has_more {
+        groups.last().map(|group| encode_cursor(group.id))
+    } else {
+        None
+    };
+
+    Ok(Json(PageEnvelope {
+        items: groups,
+        next_cursor,
+    }))
 }
 
 async fn get_groups_by_team_id(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     Path(team_id): Path<Uuid>,
 ) -> Result<Json<Vec<Group>>, ServerError> {
     let groups = sqlx::query_as!(
         Group,
         r#"
-SELECT * 
-FROM groups 
+SELECT *
+FROM groups
 WHERE team_id = $1
         "#,
         team_id
     )
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await?;
     Ok(Json(groups))
 }
 
 async fn get_users_by_group_id(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     Path(group_id): Path<Uuid>,
-) -> Result<Json<Vec<User>>, ServerError> {
+) -> Result<Json<Vec<UserProfile>>, ServerError> {
     let users = sqlx::query_as!(
         User,
         r#"
-SELECT * 
+SELECT *
 FROM users
 WHERE group_id = $1
         "#,
         group_id
     )
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await?;
 
-    Ok(Json(users))
+    Ok(Json(users.into_iter().map(UserProfile::from).collect()))
 }
 
 async fn find_user_by_form(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     Form(query): Form<UserQuery>,
-) -> Result<Json<User>, ServerError> {
+) -> Result<Json<UserProfile>, ServerError> {
     let user = sqlx::query_as!(
         User,
         r#"
-SELECT * 
-FROM users 
+SELECT *
+FROM users
 WHERE username = $1 AND primary_email_address = $2
         "#,
         query.username,
         query.email
     )
-    .fetch_one(&pool)
+    .fetch_one(&state.pool)
     .await?;
-    Ok(Json(user))
+    Ok(Json(user.into()))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/register",
+    request_body = RegisterInfo,
+    responses(
+        (status = 200, description = "Registration succeeded, tokens issued", body = TokenPair),
+        (status = 400, description = "Validation error", body = ErrorResponse),
+        (status = 401, description = "Token issuance failed", body = ErrorResponse),
+        (status = 409, description = "Username or email already taken", body = ErrorResponse),
+    )
+)]
 async fn register_user(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     ValidatedJson(register_info): ValidatedJson<RegisterInfo>,
-) -> Result<(), ServerError> {
+) -> Result<Json<TokenPair>, ServerError> {
     let salt = SaltString::generate(&mut OsRng);
     let password_hash = ARGON2
         .hash_password(register_info.password.as_bytes(), &salt)?
         .to_string();
 
-    let _ = sqlx::query!(
+    let row = sqlx::query!(
         r#"
-INSERT INTO users (username,primary_email_address,password,team_id,group_id)
-VALUES ($1,$2,$3,$4,$5)
+INSERT INTO users (username,primary_email_address,password,team_id,group_id,session_epoch)
+VALUES ($1,$2,$3,$4,$5,now())
+RETURNING id, session_epoch
         "#,
         register_info.username,
         register_info.email,
@@ -287,30 +732,252 @@ VALUES ($1,$2,$3,$4,$5)
         Option::<Uuid>::None,
         Option::<Uuid>::None
     )
-    .execute(&pool)
+    .fetch_one(&state.pool)
     .await?;
-    Ok(())
+
+    let token_pair = issue_token_pair(row.id, row.session_epoch, &state.jwt_keys)?;
+    Ok(Json(token_pair))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/login",
+    request_body = LoginInfo,
+    responses(
+        (status = 200, description = "Login succeeded, tokens issued", body = TokenPair),
+        (status = 400, description = "Validation error", body = ErrorResponse),
+        (status = 401, description = "Unknown username or incorrect password", body = ErrorResponse),
+    )
+)]
 async fn login_user(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     ValidatedJson(login_info): ValidatedJson<LoginInfo>,
-) -> Result<Json<User>, ServerError> {
-    login_info.validate()?;
+) -> Result<Json<TokenPair>, ServerError> {
     let search_user = sqlx::query_as!(
         User,
         r#"
-SELECT * 
-FROM users 
+SELECT *
+FROM users
 WHERE username=$1
         "#,
         login_info.username,
     )
-    .fetch_one(&pool)
-    .await?;
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(ServerError::Unauthorized)?;
     let hash = PasswordHash::new(&search_user.password)?;
-    ARGON2.verify_password(login_info.password.as_bytes(), &hash)?;
-    Ok(Json(search_user))
+    ARGON2
+        .verify_password(login_info.password.as_bytes(), &hash)
+        .map_err(|_| ServerError::Unauthorized)?;
+
+    let token_pair = issue_token_pair(search_user.id, search_user.session_epoch, &state.jwt_keys)?;
+    Ok(Json(token_pair))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/refresh",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "A fresh access token", body = AccessTokenResponse),
+        (status = 401, description = "Invalid, expired or revoked refresh token", body = ErrorResponse),
+    )
+)]
+async fn refresh_token(
+    State(state): State<AppState>,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<AccessTokenResponse>, ServerError> {
+    let token_data = decode::<RefreshClaims>(
+        &request.refresh_token,
+        &state.jwt_keys.decoding_key,
+        &Validation::default(),
+    )
+    .map_err(|_| ServerError::InvalidToken)?;
+    let claims = token_data.claims;
+
+    if claims.token_type != REFRESH_TOKEN_TYPE {
+        return Err(ServerError::InvalidToken);
+    }
+
+    let user = sqlx::query_as!(
+        User,
+        r#"
+SELECT *
+FROM users
+WHERE id = $1
+        "#,
+        claims.sub
+    )
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|_| ServerError::Unauthorized)?;
+
+    if user.session_epoch.timestamp() != claims.session_epoch {
+        return Err(ServerError::Unauthorized);
+    }
+
+    let now = Utc::now();
+    let access_claims = AccessClaims {
+        sub: user.id,
+        exp: (now + Duration::seconds(ACCESS_TOKEN_TTL_SECONDS)).timestamp() as usize,
+        session_epoch: claims.session_epoch,
+        token_type: ACCESS_TOKEN_TYPE.to_string(),
+    };
+    let access_token = encode(&Header::default(), &access_claims, &state.jwt_keys.encoding_key)
+        .map_err(|_| ServerError::InvalidToken)?;
+
+    Ok(Json(AccessTokenResponse { access_token }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/logout",
+    responses(
+        (status = 200, description = "All outstanding access/refresh tokens are invalidated"),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorResponse),
+    )
+)]
+async fn logout_user(
+    State(state): State<AppState>,
+    AuthenticatedUser(user): AuthenticatedUser,
+) -> Result<(), ServerError> {
+    sqlx::query!(
+        r#"
+UPDATE users
+SET session_epoch = now()
+WHERE id = $1
+        "#,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?;
+    Ok(())
+}
+
+fn normalize_avatar(bytes: &[u8], declared_content_type: Option<&str>) -> Result<Vec<u8>, ServerError> {
+    if !matches!(
+        declared_content_type,
+        Some("image/png") | Some("image/jpeg") | Some("image/webp")
+    ) {
+        return Err(ServerError::UnsupportedMediaType);
+    }
+
+    let sniffed_format =
+        image::guess_format(bytes).map_err(|_| ServerError::UnsupportedMediaType)?;
+    if !matches!(
+        sniffed_format,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP
+    ) {
+        return Err(ServerError::UnsupportedMediaType);
+    }
+
+    let image = image::load_from_memory_with_format(bytes, sniffed_format)
+        .map_err(|_| ServerError::ImageError)?;
+
+    let side = image.width().min(image.height());
+    let thumbnail = image
+        .crop_imm(
+            (image.width() - side) / 2,
+            (image.height() - side) / 2,
+            side,
+            side,
+        )
+        .resize_exact(
+            AVATAR_THUMBNAIL_SIZE,
+            AVATAR_THUMBNAIL_SIZE,
+            FilterType::Lanczos3,
+        );
+
+    let mut encoded = Vec::new();
+    thumbnail
+        .write_to(&mut std::io::Cursor::new(&mut encoded), ImageFormat::Png)
+        .map_err(|_| ServerError::ImageError)?;
+    Ok(encoded)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/users/{user_id}/avatar",
+    params(("user_id" = Uuid, Path, description = "Id of the user to upload an avatar for")),
+    responses(
+        (status = 200, description = "Avatar stored"),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorResponse),
+        (status = 403, description = "Not the avatar's owner", body = ErrorResponse),
+        (status = 415, description = "Unsupported image type", body = ErrorResponse),
+        (status = 422, description = "Not a valid image", body = ErrorResponse),
+    )
+)]
+async fn upload_avatar(
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+    AuthenticatedUser(user): AuthenticatedUser,
+    mut multipart: Multipart,
+) -> Result<(), ServerError> {
+    if user.id != user_id {
+        return Err(ServerError::Forbidden);
+    }
+
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|_| ServerError::ImageError)?
+        .ok_or(ServerError::ImageError)?;
+
+    let declared_content_type = field.content_type().map(str::to_string);
+    if let Some(file_name) = field.file_name() {
+        let guessed_from_name = mime_guess::from_path(file_name).first();
+        if let (Some(declared), Some(guessed)) = (&declared_content_type, &guessed_from_name) {
+            if declared != guessed.essence_str() {
+                return Err(ServerError::UnsupportedMediaType);
+            }
+        }
+    }
+
+    let bytes = field.bytes().await.map_err(|_| ServerError::ImageError)?;
+    let avatar = normalize_avatar(&bytes, declared_content_type.as_deref())?;
+
+    sqlx::query!(
+        r#"
+INSERT INTO avatars (user_id, content_type, data)
+VALUES ($1, $2, $3)
+ON CONFLICT (user_id) DO UPDATE SET content_type = $2, data = $3, updated_at = now()
+        "#,
+        user_id,
+        AVATAR_CONTENT_TYPE,
+        avatar
+    )
+    .execute(&state.pool)
+    .await?;
+
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/users/{user_id}/avatar",
+    params(("user_id" = Uuid, Path, description = "Id of the user whose avatar to fetch")),
+    responses(
+        (status = 200, description = "The avatar image", content_type = "image/png"),
+        (status = 404, description = "The user has no avatar", body = ErrorResponse),
+    )
+)]
+async fn get_avatar(
+    State(state): State<AppState>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Response, ServerError> {
+    let avatar = sqlx::query!(
+        r#"
+SELECT content_type, data
+FROM avatars
+WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .ok_or(ServerError::NotFound)?;
+
+    Ok(([(header::CONTENT_TYPE, avatar.content_type)], avatar.data).into_response())
 }
 
 #[cfg(test)]
@@ -327,11 +994,16 @@ mod tests {
 
     async fn create_test_app() -> Router {
         let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let jwt_secret =
+            std::env::var("JWT_SECRET").unwrap_or_else(|_| "test-secret".to_string());
         let pool = PgPool::connect(&database_url).await.unwrap();
+        let jwt_keys = JwtKeys::from_secret(jwt_secret.as_bytes());
+        let state = AppState { pool, jwt_keys };
         Router::new()
             .route("/api/register", post(register_user))
             .route("/api/users/find", post(find_user_by_form))
-            .with_state(pool)
+            .route("/api/users", get(get_users))
+            .with_state(state)
     }
 
     async fn get_html(response: Response<Body>) -> String {
@@ -347,7 +1019,7 @@ mod tests {
         let _ = sqlx::query_as!(
             User,
             r#"
-DELETE FROM users 
+DELETE FROM users
 WHERE username = $1
             "#,
             info.username,
@@ -363,8 +1035,8 @@ WHERE username = $1
         let _ = sqlx::query_as!(
             User,
             r#"
-INSERT INTO users (username,primary_email_address,password,team_id,group_id)
-VALUES ($1,$2,$3,$4,$5)
+INSERT INTO users (username,primary_email_address,password,team_id,group_id,session_epoch)
+VALUES ($1,$2,$3,$4,$5,now())
             "#,
             "haoxiangzhou",
             "haoxiangzhou@example.com",
@@ -376,6 +1048,42 @@ VALUES ($1,$2,$3,$4,$5)
         .await
         .unwrap();
     }
+
+    async fn create_named_user(username: &str, email: &str) {
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        let _ = sqlx::query_as!(
+            User,
+            r#"
+INSERT INTO users (username,primary_email_address,password,team_id,group_id,session_epoch)
+VALUES ($1,$2,$3,$4,$5,now())
+            "#,
+            username,
+            email,
+            "P2025zhx",
+            Option::<Uuid>::None,
+            Option::<Uuid>::None
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
+    async fn drop_named_user(username: &str) {
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = PgPool::connect(&database_url).await.unwrap();
+        let _ = sqlx::query!(
+            r#"
+DELETE FROM users
+WHERE username = $1
+            "#,
+            username,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+
     #[tokio::test]
     async fn test_register_user_success() {
         let app = create_test_app().await;
@@ -428,6 +1136,46 @@ VALUES ($1,$2,$3,$4,$5)
         );
     }
 
+    #[tokio::test]
+    async fn test_register_user_duplicate_conflict() {
+        let app = create_test_app().await;
+        let register_info = RegisterInfo {
+            username: "duplicate_user".to_string(),
+            email: "duplicate_user@example.com".to_string(),
+            password: "P2025zhx".to_string(),
+            confirm_password: "P2025zhx".to_string(),
+        };
+
+        let first_response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/register")
+                    .method("POST")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::to_string(&register_info).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(first_response.status(), StatusCode::OK);
+
+        let second_response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/register")
+                    .method("POST")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(serde_json::to_string(&register_info).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(second_response.status(), StatusCode::CONFLICT);
+
+        drop_data(register_info).await;
+    }
+
     #[tokio::test]
     async fn test_find_user_by_form_success() {
         create_data().await;
@@ -454,4 +1202,53 @@ VALUES ($1,$2,$3,$4,$5)
         })
         .await;
     }
+
+    #[tokio::test]
+    async fn test_get_users_pagination_envelope() {
+        create_named_user("page_test_a", "page_test_a@example.com").await;
+        create_named_user("page_test_b", "page_test_b@example.com").await;
+        let app = create_test_app().await;
+
+        let first_page = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?limit=1")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(first_page.status(), StatusCode::OK);
+        let first_page: serde_json::Value =
+            serde_json::from_str(&get_html(first_page).await).unwrap();
+        let items = first_page["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        let next_cursor = first_page["next_cursor"]
+            .as_str()
+            .expect("a next_cursor is returned when more rows remain")
+            .to_string();
+        let first_id = items[0]["id"].as_str().unwrap().to_string();
+
+        let second_page = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/api/users?limit=1&cursor={next_cursor}"))
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(second_page.status(), StatusCode::OK);
+        let second_page: serde_json::Value =
+            serde_json::from_str(&get_html(second_page).await).unwrap();
+        let items = second_page["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_ne!(items[0]["id"].as_str().unwrap(), first_id);
+
+        drop_named_user("page_test_a").await;
+        drop_named_user("page_test_b").await;
+    }
 }